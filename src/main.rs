@@ -1,15 +1,25 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::ffi::OsString;
 use std::fmt::Debug;
 use std::fs::{DirEntry, metadata, read_dir, read_to_string};
-use std::io;
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio, ExitStatus};
+use std::str::FromStr;
 use std::string::FromUtf8Error;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
 use clap::Clap;
+use humantime::Duration as HumanDuration;
+use serde::Deserialize;
 use thiserror::Error;
 
+/// How long an executable env file is allowed to run before it's killed.
+const DEFAULT_TIMEOUT: &str = "5s";
+
 /// Load environment variables from DIR (or ~/.envdir).
 ///
 /// For each non-directory entry in DIR, this will output a brief shell script
@@ -26,7 +36,8 @@ use thiserror::Error;
 ///
 ///     eval "$(envdir-helper)"
 ///
-/// The generated output is compatible with sh, and thus with bash and zsh.
+/// The generated script matches the syntax of --shell, or the config file's
+/// shell, or the shell detected from $SHELL.
 #[derive(Clap)]
 #[clap(version=env!("CARGO_PKG_VERSION"))]
 struct Opts {
@@ -35,6 +46,15 @@ struct Opts {
     /// Export generated environment variables [default: true]
     #[clap(long)]
     export: Option<bool>,
+    /// Shell syntax to emit: sh, fish, csh, or powershell [default: the config file's shell, else detected from $SHELL]
+    #[clap(long)]
+    shell: Option<Shell>,
+    /// Maximum time to let an executable env file run before killing it
+    #[clap(long, default_value = DEFAULT_TIMEOUT)]
+    timeout: HumanDuration,
+    /// Config file to read defaults from [default: ~/.envdir-helper.toml]
+    #[clap(long)]
+    config: Option<PathBuf>,
 }
 
 #[derive(Error, Debug)]
@@ -45,6 +65,8 @@ enum EnvdirError {
     EnvdirListFailed(#[from] io::Error),
     #[error("failed to decode a filename")]
     PathStringError(#[from] PathStringError),
+    #[error("failed to load config file")]
+    ConfigError(#[from] ConfigError),
 }
 
 const SELF: &str = env!("CARGO_BIN_NAME");
@@ -52,25 +74,57 @@ const SELF: &str = env!("CARGO_BIN_NAME");
 fn main() -> Result<(), EnvdirError> {
     let opts: Opts = Opts::parse();
 
+    let config = load_config(opts.config.as_deref())?;
+
     let envdir = match opts.envdir {
         None => default_envdir()?,
         Some(envdir) => envdir,
     };
 
-    let output_fn = match opts.export {
-        None => detect_env_script(&envdir)?,
-        Some(true) => export_env_script,
-        Some(false) => no_export_env_script,
+    let shell = opts.shell.or(config.shell).unwrap_or_else(Shell::detect);
+
+    let default_export = match opts.export.or(config.export) {
+        Some(export) => export,
+        None => detect_export(&envdir)?,
     };
 
-    for path in read_dir(envdir)?
+    let timeout: Duration = opts.timeout.into();
+
+    let ignore_patterns = compile_ignore_patterns(&config.ignore);
+
+    let mut emitted: BTreeMap<String, String> = BTreeMap::new();
+
+    let mut entries: Vec<PathBuf> = read_dir(envdir)?
         .filter_map(skip_failing_direntry)
         .map(|entry| entry.path())
         .filter(|path| !path.is_dir())
-    {
+        .collect();
+    // `read_dir` makes no ordering guarantee, but `${OTHER_VAR}` references
+    // are documented to see values emitted by files processed earlier, so
+    // entries need a well-defined, reproducible order.
+    entries.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+
+    for path in entries {
         let name = path_to_string(&path)?;
-        match env_content(&path) {
-            Ok(content) => println!("{}", output_fn(name, &content)),
+
+        if is_ignored(name, &ignore_patterns) {
+            continue;
+        }
+
+        if !is_valid_var_name(name) {
+            eprintln!("{}: skipping {:?}: not a valid environment variable name", SELF, name);
+            continue;
+        }
+
+        let file_override = config.file.get(name);
+        let forced_program = file_override.and_then(|o| o.program);
+        let export = file_override.and_then(|o| o.export).unwrap_or(default_export);
+
+        match env_content(&path, timeout, &emitted, forced_program) {
+            Ok(content) => {
+                println!("{}", shell.emit(name, &content, export));
+                emitted.insert(name.to_string(), content);
+            }
             Err(e) => eprintln!("{}: error reading env value for {:?}: {:?}", SELF, name, e),
         };
     }
@@ -99,27 +153,242 @@ fn default_envdir() -> Result<PathBuf, DefaultDirError> {
     Ok(envdir)
 }
 
-type ExportScript = fn(&str, &str) -> String;
+/// Options read from `~/.envdir-helper.toml` (or `--config`). CLI flags take
+/// precedence over these when both are set.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Config {
+    export: Option<bool>,
+    shell: Option<Shell>,
+    /// Glob patterns (matched against the entry's filename) to skip, e.g.
+    /// editor backup files like `*~` or `.DS_Store`.
+    #[serde(default)]
+    ignore: Vec<String>,
+    /// Per-filename overrides, keyed by the entry's filename.
+    #[serde(default)]
+    file: BTreeMap<String, FileOverride>,
+}
 
-fn detect_env_script(path: &Path) -> Result<ExportScript, PathStringError> {
-    let file_name = path_to_string(path)?;
-    Ok(if file_name.ends_with("rc") {
-        no_export_env_script
-    } else {
-        export_env_script
-    })
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct FileOverride {
+    /// Force this entry to be treated as a program (or as plain content),
+    /// overriding the execute-bit heuristic.
+    program: Option<bool>,
+    export: Option<bool>,
+}
+
+#[derive(Error, Debug)]
+enum ConfigError {
+    #[error("failed to read config file: {0}")]
+    IoError(#[from] io::Error),
+    #[error("failed to parse config file: {0}")]
+    ParseError(#[from] toml::de::Error),
+}
+
+fn default_config_path() -> Option<PathBuf> {
+    let mut path = PathBuf::from(env::var("HOME").ok()?);
+    path.push(".envdir-helper.toml");
+    Some(path)
+}
+
+/// Load `path`, or the default config path if none was given explicitly. A
+/// missing default config is not an error; a missing `--config` path is.
+fn load_config(path: Option<&Path>) -> Result<Config, ConfigError> {
+    let contents = match path {
+        Some(path) => Some(read_to_string(path)?),
+        None => match default_config_path() {
+            Some(path) => read_to_string(path).ok(),
+            None => None,
+        },
+    };
+
+    match contents {
+        Some(contents) => Ok(toml::from_str(&contents)?),
+        None => Ok(Config::default()),
+    }
+}
+
+/// Compile the config's `ignore` glob patterns once per run, rather than
+/// once per envdir entry. Invalid patterns are dropped with a warning rather
+/// than silently treated as non-matching.
+fn compile_ignore_patterns(patterns: &[String]) -> Vec<glob::Pattern> {
+    patterns.iter().filter_map(|pattern| {
+        match glob::Pattern::new(pattern) {
+            Ok(pattern) => Some(pattern),
+            Err(e) => {
+                eprintln!("{}: invalid ignore pattern {:?}: {}", SELF, pattern, e);
+                None
+            }
+        }
+    }).collect()
+}
+
+fn is_ignored(name: &str, patterns: &[glob::Pattern]) -> bool {
+    patterns.iter().any(|pattern| pattern.matches(name))
+}
+
+/// A shell output syntax. Each variant owns its own assignment syntax (with
+/// and without export) and its own quoting rules, since `shlex::quote` only
+/// produces POSIX-compatible quoting and would corrupt values for the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shell {
+    /// sh, and the POSIX-compatible shells that build on it (bash, zsh).
+    Sh,
+    Fish,
+    /// csh and tcsh, which share assignment syntax.
+    Csh,
+    PowerShell,
+}
+
+impl Shell {
+    /// Pick a shell from `$SHELL`, falling back to `Sh` if it's unset or
+    /// unrecognized.
+    fn detect() -> Shell {
+        match env::var("SHELL") {
+            Ok(shell) => Shell::from_shell_path(&shell),
+            Err(_) => Shell::Sh,
+        }
+    }
+
+    fn from_shell_path(path: &str) -> Shell {
+        match Path::new(path).file_name().and_then(|name| name.to_str()) {
+            Some("fish") => Shell::Fish,
+            Some("csh") | Some("tcsh") => Shell::Csh,
+            Some("pwsh") | Some("powershell") => Shell::PowerShell,
+            _ => Shell::Sh,
+        }
+    }
+
+    /// Render an assignment of `content` to `name` in this shell's syntax.
+    fn emit(self, name: &str, content: &str, export: bool) -> String {
+        match self {
+            Shell::Sh => {
+                let name = shlex::quote(name);
+                let content = shlex::quote(content);
+                if export {
+                    format!("{}={}; export {}", name, content, name)
+                } else {
+                    format!("{}={}", name, content)
+                }
+            }
+            Shell::Fish => {
+                let flag = if export { "-gx" } else { "-g" };
+                format!("set {} {} {}", flag, name, quote_fish(content))
+            }
+            Shell::Csh => {
+                if export {
+                    format!("setenv {} {}", name, quote_csh(content))
+                } else {
+                    format!("set {}={}", name, quote_csh(content))
+                }
+            }
+            Shell::PowerShell => {
+                let prefix = if export { "$env:" } else { "$" };
+                format!("{}{} = {}", prefix, name, quote_powershell(content))
+            }
+        }
+    }
+}
+
+impl FromStr for Shell {
+    type Err = ShellParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sh" => Ok(Shell::Sh),
+            "fish" => Ok(Shell::Fish),
+            "csh" | "tcsh" => Ok(Shell::Csh),
+            "powershell" | "pwsh" => Ok(Shell::PowerShell),
+            other => Err(ShellParseError(other.to_string())),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("unknown shell {0:?}; expected one of: sh, fish, csh, powershell")]
+struct ShellParseError(String);
+
+/// Deserialize through `FromStr` so a `shell = "..."` config entry accepts
+/// the same spellings (including aliases like "tcsh" and "pwsh") as --shell.
+impl<'de> Deserialize<'de> for Shell {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Quote a value for fish. Fish single quotes treat `\` as an escape
+/// character for `\` and `'` (unlike POSIX single quotes, where backslash is
+/// literal), so `shlex::quote`'s POSIX-style quoting would leave an
+/// unescaped trailing backslash able to escape the closing quote.
+fn quote_fish(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('\'');
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '\'' => quoted.push_str("\\'"),
+            other => quoted.push(other),
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// Quote a value for csh/tcsh. Unlike POSIX single quotes, csh single quotes
+/// do not suppress `!` history expansion, so a value containing `!` needs
+/// its own `\!` escape (which csh's history mechanism strips even inside
+/// single quotes) on top of the usual POSIX-style quoting. Existing
+/// backslashes are doubled first so a value that already ends in `\!`
+/// doesn't collapse into an unescaped, history-triggering `!`.
+fn quote_csh(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('\'');
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '!' => quoted.push_str("\\!"),
+            '\'' => quoted.push_str("'\\''"),
+            other => quoted.push(other),
+        }
+    }
+    quoted.push('\'');
+    quoted
 }
 
-fn no_export_env_script(name: &str, content: &str) -> String {
-    let name = shlex::quote(name);
-    let content= shlex::quote(content);
-    format!("{}={}", name, content)
+/// Quote a value for PowerShell, whose single-quoted strings escape an
+/// embedded quote by doubling it, not by backslash-escaping it.
+fn quote_powershell(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
 }
 
-fn export_env_script(name: &str, content: &str) -> String {
-    let name = shlex::quote(name);
-    let content= shlex::quote(content);
-    format!("{}={}; export {}", name, content, name)
+/// Whether `name` is safe to interpolate unquoted into the shells' assignment
+/// syntax (`set -gx NAME ...`, `setenv NAME ...`, `$env:NAME = ...`). Unlike
+/// `content`, which every `Shell::emit` arm quotes, `name` is interpolated
+/// raw by the fish, csh and PowerShell arms -- and the `Sh` arm's
+/// `shlex::quote` of it would break its `NAME=VALUE` assignment form for any
+/// name that actually needed quoting, while quoting isn't meaningful for
+/// PowerShell's `$env:` syntax -- so entries whose filename isn't a valid
+/// identifier are rejected instead.
+fn is_valid_var_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// An envdir whose name ends in `rc` is assumed to be loaded by a shell
+/// startup file that runs once at login, so its variables shouldn't be
+/// re-exported to every child process.
+fn detect_export(path: &Path) -> Result<bool, PathStringError> {
+    let file_name = path_to_string(path)?;
+    Ok(!file_name.ends_with("rc"))
 }
 
 #[derive(Error, Debug)]
@@ -145,20 +414,41 @@ enum EnvContentError {
     #[error("program produced non-UTF-8 output: {0}")]
     NonUnicodeOutput(#[from] FromUtf8Error),
     #[error("program {0:?} exited with status: {1}")]
-    ProgramFailed(PathBuf, ExitStatus)
+    ProgramFailed(PathBuf, ExitStatus),
+    #[error("program {0:?} did not finish within {1:?}")]
+    Timeout(PathBuf, Duration),
+    #[error("failed to expand template placeholders: {0}")]
+    TemplateError(#[from] TemplateError),
 }
 
-fn env_content(path: &Path) -> Result<String, EnvContentError> {
-    let mut content = if is_program(path)? {
-        env_program_content(path)?
+/// `emitted` holds the name/value pairs already emitted earlier in this run,
+/// so a file's `${OTHER_VAR}` references can see variables set by files read
+/// before it. `forced_program` overrides the execute-bit heuristic when the
+/// config file pins this entry to be treated as a program (or as plain
+/// content) explicitly.
+fn env_content(
+    path: &Path,
+    timeout: Duration,
+    emitted: &BTreeMap<String, String>,
+    forced_program: Option<bool>,
+) -> Result<String, EnvContentError> {
+    let run_as_program = match forced_program {
+        Some(forced) => forced,
+        None => is_program(path)?,
+    };
+
+    let content = if run_as_program {
+        env_program_content(path, timeout)?
     } else {
         env_file_content(path)?
     };
 
+    let mut content = expand_template(&content, emitted)?;
+
     if content.ends_with("\n") {
         content.pop();
     }
-    
+
     Ok(content)
 }
 
@@ -173,23 +463,340 @@ fn is_program(path: &Path) -> io::Result<bool> {
     Ok(permissions.mode() & EXEC_MASK != 0)
 }
 
-fn env_program_content(path: &Path) -> Result<String, EnvContentError> {
+/// Run `path` and collect its stdout, killing it if it doesn't finish within
+/// `timeout`. `Command::output` has no timeout of its own, and since the
+/// intended use is `eval "$(envdir-helper)"` in a shell profile, one wedged
+/// program would otherwise hang every new shell.
+fn env_program_content(path: &Path, timeout: Duration) -> Result<String, EnvContentError> {
     use EnvContentError::*;
 
-    let output = Command::new(path)
+    let deadline = std::time::Instant::now() + timeout;
+    let mut child = Command::new(path)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::inherit())
-        .output()?;
-    
-    if output.status.success() {
-        let output = String::from_utf8(output.stdout)?;
+        .spawn()?;
+
+    // Nothing is ever written to the child's stdin; drop our end of the pipe
+    // immediately so a program that reads stdin to EOF (e.g. `cat`) sees EOF
+    // right away instead of blocking for the full timeout, mirroring what
+    // `Command::output()` (via `wait_with_output`) used to do for us.
+    drop(child.stdin.take());
+
+    let mut stdout = child.stdout.take().expect("child stdout was piped");
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let result = stdout.read_to_end(&mut buf).map(|_| buf);
+        let _ = sender.send(result);
+    });
+
+    let stdout = match receiver.recv_timeout(timeout) {
+        Ok(result) => result?,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            child.kill()?;
+            child.wait()?;
+            return Err(Timeout(path.to_path_buf(), timeout));
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            unreachable!("reader thread always sends a result before exiting")
+        }
+    };
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if std::time::Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Err(Timeout(path.to_path_buf(), timeout));
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    if status.success() {
+        let output = String::from_utf8(stdout)?;
         Ok(output)
     } else {
-        Err(ProgramFailed(path.to_path_buf(), output.status))
+        Err(ProgramFailed(path.to_path_buf(), status))
     }
 }
 
 fn env_file_content(path: &Path) -> Result<String, EnvContentError> {
     Ok(read_to_string(path)?)
 }
+
+/// A template function available inside `{{ name(args...) }}` placeholders,
+/// carrying its own arity so callers can validate a call before invoking it.
+enum Function {
+    Nullary(fn() -> Result<String, String>),
+    Unary(fn(&str) -> Result<String, String>),
+    Binary(fn(&str, &str) -> Result<String, String>),
+}
+
+impl Function {
+    fn argc(&self) -> usize {
+        match self {
+            Function::Nullary(_) => 0,
+            Function::Unary(_) => 1,
+            Function::Binary(_) => 2,
+        }
+    }
+
+    fn call(&self, args: &[String]) -> Result<String, String> {
+        match (self, args) {
+            (Function::Nullary(f), []) => f(),
+            (Function::Unary(f), [a]) => f(a),
+            (Function::Binary(f), [a, b]) => f(a, b),
+            _ => unreachable!("arity is checked by the caller before call() is invoked"),
+        }
+    }
+}
+
+fn function_table() -> BTreeMap<&'static str, Function> {
+    let mut table: BTreeMap<&'static str, Function> = BTreeMap::new();
+    table.insert("os", Function::Nullary(fn_os));
+    table.insert("os_family", Function::Nullary(fn_os_family));
+    table.insert("arch", Function::Nullary(fn_arch));
+    table.insert("env_var", Function::Unary(fn_env_var));
+    table.insert("env_var_or_default", Function::Binary(fn_env_var_or_default));
+    table
+}
+
+fn fn_os() -> Result<String, String> {
+    Ok(env::consts::OS.to_string())
+}
+
+fn fn_os_family() -> Result<String, String> {
+    Ok(env::consts::FAMILY.to_string())
+}
+
+fn fn_arch() -> Result<String, String> {
+    Ok(env::consts::ARCH.to_string())
+}
+
+fn fn_env_var(name: &str) -> Result<String, String> {
+    env::var(name).map_err(|_| format!("environment variable {:?} is not set", name))
+}
+
+fn fn_env_var_or_default(name: &str, default: &str) -> Result<String, String> {
+    Ok(env::var(name).unwrap_or_else(|_| default.to_string()))
+}
+
+#[derive(Error, Debug)]
+enum TemplateError {
+    #[error("unterminated {{{{...}}}} or ${{...}} placeholder")]
+    UnterminatedPlaceholder,
+    #[error("malformed function call {0:?}: expected name(arg, ...)")]
+    MalformedCall(String),
+    #[error("unknown template function: {0}")]
+    UnknownFunction(String),
+    #[error("{0} expects {1} argument(s), got {2}")]
+    WrongArity(String, usize, usize),
+    #[error("{0} failed: {1}")]
+    FunctionFailed(String, String),
+    #[error("unresolved variable: {0}")]
+    UnresolvedVariable(String),
+}
+
+/// Expand `{{ function(args...) }}` and `${VAR}` placeholders in `content`.
+/// `\{{` and `\$` escape a literal `{{` or `$` so binary-ish content can pass
+/// through untouched. Trailing-newline stripping in `env_content` happens
+/// after this runs, so a placeholder may itself expand to a newline.
+fn expand_template(content: &str, emitted: &BTreeMap<String, String>) -> Result<String, TemplateError> {
+    let functions = function_table();
+    let chars: Vec<char> = content.chars().collect();
+    let mut result = String::with_capacity(content.len());
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if matches!(chars.get(i + 1), Some('{') | Some('$')) => {
+                result.push(chars[i + 1]);
+                i += 2;
+            }
+            '$' if chars.get(i + 1) == Some(&'{') => {
+                let end = find(&chars, i + 2, '}').ok_or(TemplateError::UnterminatedPlaceholder)?;
+                let name: String = chars[i + 2..end].iter().collect();
+                result.push_str(&resolve_var(name.trim(), emitted)?);
+                i = end + 1;
+            }
+            '{' if chars.get(i + 1) == Some(&'{') => {
+                let end = find_closing_braces(&chars, i + 2)
+                    .ok_or(TemplateError::UnterminatedPlaceholder)?;
+                let body: String = chars[i + 2..end].iter().collect();
+                result.push_str(&call_function(body.trim(), &functions)?);
+                i = end + 2;
+            }
+            c => {
+                result.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+fn resolve_var(name: &str, emitted: &BTreeMap<String, String>) -> Result<String, TemplateError> {
+    if let Some(value) = emitted.get(name) {
+        return Ok(value.clone());
+    }
+    env::var(name).map_err(|_| TemplateError::UnresolvedVariable(name.to_string()))
+}
+
+fn call_function(
+    body: &str,
+    functions: &BTreeMap<&'static str, Function>,
+) -> Result<String, TemplateError> {
+    let open = body.find('(');
+    let close = body.rfind(')');
+    let (open, close) = match (open, close) {
+        (Some(open), Some(close)) if open < close => (open, close),
+        _ => return Err(TemplateError::MalformedCall(body.to_string())),
+    };
+
+    let name = body[..open].trim();
+    let args: Vec<String> = {
+        let raw = body[open + 1..close].trim();
+        if raw.is_empty() {
+            Vec::new()
+        } else {
+            raw.split(',').map(|arg| arg.trim().to_string()).collect()
+        }
+    };
+
+    let function = functions
+        .get(name)
+        .ok_or_else(|| TemplateError::UnknownFunction(name.to_string()))?;
+
+    if args.len() != function.argc() {
+        return Err(TemplateError::WrongArity(name.to_string(), function.argc(), args.len()));
+    }
+
+    function
+        .call(&args)
+        .map_err(|e| TemplateError::FunctionFailed(name.to_string(), e))
+}
+
+fn find(chars: &[char], from: usize, target: char) -> Option<usize> {
+    chars[from..].iter().position(|&c| c == target).map(|pos| pos + from)
+}
+
+/// Find the first `}}` at or after `from`.
+fn find_closing_braces(chars: &[char], from: usize) -> Option<usize> {
+    (from..chars.len().saturating_sub(1)).find(|&i| chars[i] == '}' && chars[i + 1] == '}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_fish_wraps_plain_values() {
+        assert_eq!(quote_fish("plain"), "'plain'");
+    }
+
+    #[test]
+    fn quote_fish_escapes_embedded_quote() {
+        assert_eq!(quote_fish("a'b"), r"'a\'b'");
+    }
+
+    #[test]
+    fn quote_fish_escapes_embedded_backslash() {
+        assert_eq!(quote_fish(r"a\b"), r"'a\\b'");
+    }
+
+    #[test]
+    fn quote_csh_wraps_plain_values() {
+        assert_eq!(quote_csh("plain"), "'plain'");
+    }
+
+    #[test]
+    fn quote_csh_escapes_bang_to_avoid_history_expansion() {
+        assert_eq!(quote_csh("a!b"), r"'a\!b'");
+    }
+
+    #[test]
+    fn quote_csh_does_not_let_an_existing_backslash_unescape_the_bang() {
+        // A literal `\!` in the input must not collapse into an unescaped
+        // `!` once quoted: the pre-existing backslash is doubled so it
+        // stays literal, leaving a fresh, odd-length run of backslashes to
+        // escape the bang itself.
+        assert_eq!(quote_csh(r"a\!b"), r"'a\\\!b'");
+    }
+
+    #[test]
+    fn quote_powershell_wraps_plain_values() {
+        assert_eq!(quote_powershell("plain"), "'plain'");
+    }
+
+    #[test]
+    fn quote_powershell_doubles_embedded_quote() {
+        assert_eq!(quote_powershell("it's"), "'it''s'");
+    }
+
+    #[test]
+    fn expand_template_passes_through_plain_content() {
+        let emitted = BTreeMap::new();
+        assert_eq!(expand_template("plain value", &emitted).unwrap(), "plain value");
+    }
+
+    #[test]
+    fn expand_template_resolves_emitted_values() {
+        let mut emitted = BTreeMap::new();
+        emitted.insert("FOO".to_string(), "bar".to_string());
+        assert_eq!(expand_template("${FOO}", &emitted).unwrap(), "bar");
+    }
+
+    #[test]
+    fn expand_template_calls_nullary_function() {
+        let emitted = BTreeMap::new();
+        assert_eq!(expand_template("{{os()}}", &emitted).unwrap(), env::consts::OS);
+    }
+
+    #[test]
+    fn expand_template_unterminated_var_placeholder_errors() {
+        let emitted = BTreeMap::new();
+        assert!(matches!(
+            expand_template("${FOO", &emitted),
+            Err(TemplateError::UnterminatedPlaceholder)
+        ));
+    }
+
+    #[test]
+    fn expand_template_unterminated_function_placeholder_errors() {
+        let emitted = BTreeMap::new();
+        assert!(matches!(
+            expand_template("{{os()", &emitted),
+            Err(TemplateError::UnterminatedPlaceholder)
+        ));
+    }
+
+    #[test]
+    fn expand_template_unknown_function_errors() {
+        let emitted = BTreeMap::new();
+        assert!(matches!(
+            expand_template("{{nope()}}", &emitted),
+            Err(TemplateError::UnknownFunction(_))
+        ));
+    }
+
+    #[test]
+    fn expand_template_wrong_arity_errors() {
+        let emitted = BTreeMap::new();
+        assert!(matches!(
+            expand_template("{{os(1)}}", &emitted),
+            Err(TemplateError::WrongArity(_, 0, 1))
+        ));
+    }
+
+    #[test]
+    fn expand_template_malformed_call_does_not_panic() {
+        let emitted = BTreeMap::new();
+        assert!(matches!(
+            expand_template("{{)(}}", &emitted),
+            Err(TemplateError::MalformedCall(_))
+        ));
+    }
+}